@@ -0,0 +1,33 @@
+use cln_grpc::pb::node_server::NodeServer;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tonic_health::server::HealthReporter;
+
+/// How often the health task re-checks that `rpc_path` is reachable.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Keeps the `grpc.health.v1.Health` status of [`NodeServer`] in sync
+/// with whether `lightningd`'s RPC socket is reachable, since the
+/// plugin's own availability tracks a separately-restarting `lightningd`.
+/// Runs until `shutdown_rx` fires.
+pub async fn run(
+    rpc_path: PathBuf,
+    mut reporter: HealthReporter,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    loop {
+        if tokio::net::UnixStream::connect(&rpc_path).await.is_ok() {
+            reporter.set_serving::<NodeServer<cln_grpc::Server>>().await;
+        } else {
+            reporter
+                .set_not_serving::<NodeServer<cln_grpc::Server>>()
+                .await;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+            _ = shutdown_rx.recv() => return,
+        }
+    }
+}
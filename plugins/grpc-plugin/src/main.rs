@@ -2,11 +2,33 @@ use anyhow::{Context, Result};
 use cln_grpc::pb::node_server::NodeServer;
 use cln_plugin::{options, Builder, Plugin};
 use cln_rpc::notifications::Notification;
+use futures::FutureExt;
 use log::{debug, warn};
-use router::GrpcRouterConfig;
+use metrics::Metrics;
+use router::{GrpcRouterConfig, Transport};
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::broadcast;
+use tokio_stream::wrappers::UnixListenerStream;
 
+/// How long `main` waits for `run_interface` and the metrics server to
+/// drain in-flight requests after a shutdown signal before giving up on
+/// them and exiting anyway.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Minimum time between consecutive "broadcast buffer is filling up"
+/// warnings, so a sustained burst of notifications logs once rather
+/// than once per notification.
+const HIGH_WATER_MARK_WARN_INTERVAL: Duration = Duration::from_secs(30);
+
+mod health;
+mod metrics;
+mod panic_guard;
 mod router;
 mod tls;
 
@@ -16,18 +38,25 @@ struct PluginState {
     identity: tls::Identity,
     ca_cert: Vec<u8>,
     events: broadcast::Sender<cln_rpc::notifications::Notification>,
+    metrics: Metrics,
+    /// Capacity of `events`, and the occupancy (in messages) at or above which
+    /// `handle_notification` starts warning that subscribers risk
+    /// missing notifications.
+    events_buffer_size: usize,
+    events_high_water_mark: usize,
+    last_high_water_warn: Arc<Mutex<Option<Instant>>>,
 }
 
-const OPTION_GRPC_PORT: options::DefaultIntegerConfigOption = options::ConfigOption::new_i64_with_default(
+pub(crate) const OPTION_GRPC_PORT: options::DefaultIntegerConfigOption = options::ConfigOption::new_i64_with_default(
     "grpc-port",
     9736,
     "Which port should the grpc plugin listen for incoming connections?"
 );
 
-const OPTION_GRPC_HOST: options::DefaultStringConfigOption = options::ConfigOption::new_str_with_default(
+pub(crate) const OPTION_GRPC_HOST: options::DefaultStringConfigOption = options::ConfigOption::new_str_with_default(
     "grpc-host",
     "127.0.0.1",
-    "Which host should the grpc listen for incomming connections?"
+    "Which host should the grpc listen for incomming connections? Accepts a bare IP address for a TCP listener, or a 'unix:///path/to/socket' URI to listen on a Unix domain socket instead (TLS is disabled in that case, since filesystem permissions already gate access)."
 );
 
 const OPTION_GRPC_MSG_BUFFER_SIZE : options::DefaultIntegerConfigOption = options::ConfigOption::new_i64_with_default(
@@ -35,6 +64,17 @@ const OPTION_GRPC_MSG_BUFFER_SIZE : options::DefaultIntegerConfigOption = option
     1024,
     "Number of notifications which can be stored in the grpc message buffer. Notifications can be skipped if this buffer is full");
 
+const OPTION_GRPC_METRICS_PORT: options::DefaultIntegerConfigOption = options::ConfigOption::new_i64_with_default(
+    "grpc-metrics-port",
+    9737,
+    "Which port should the plugin serve Prometheus text-format metrics on (GET /metrics)?"
+);
+
+const OPTION_GRPC_NOTIFY_HIGH_WATER_MARK: options::DefaultIntegerConfigOption = options::ConfigOption::new_i64_with_default(
+    "grpc-notify-high-water-mark",
+    80,
+    "Percentage of 'grpc-msg-buffer-size' occupancy at which a rate-limited warning is logged that subscribers risk missing notifications");
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     debug!("Starting grpc plugin");
@@ -45,6 +85,17 @@ async fn main() -> Result<()> {
         .option(OPTION_GRPC_PORT)
         .option(OPTION_GRPC_HOST)
         .option(OPTION_GRPC_MSG_BUFFER_SIZE)
+        .option(OPTION_GRPC_METRICS_PORT)
+        .option(OPTION_GRPC_NOTIFY_HIGH_WATER_MARK)
+        // NOTE: request chunk0-5 ("forward newly-added CLN notifications
+        // to clients without a plugin recompile") is not implementable in
+        // this crate: it needs a generic StreamAny RPC that lives on
+        // cln_grpc::Server, an external crate not present in this tree.
+        // `.subscribe("*", ...)` alone doesn't deliver it (unmatched
+        // notifications still just get logged and dropped below), and
+        // flipping it on is exactly what this TODO warns against, with no
+        // test here to show it's now safe. Stick to the known, exercised
+        // set of notification kinds until that's addressed upstream.
         // TODO: Use the catch-all subscribe method
         // However, doing this breaks the plugin at the time begin
         // We should fix this
@@ -63,11 +114,7 @@ async fn main() -> Result<()> {
     };
 
     let router_config = match GrpcRouterConfig::from_configured_plugin(&plugin) {
-        Ok(Some(cfg)) => cfg,
-        Ok(None) => {
-            log::info!("Running on default 'grpc-port' 9736.");
-            return Ok(());
-        }
+        Ok(cfg) => cfg,
         Err(err) => {
           log::warn!("{:?}", err);
           plugin.disable(&format!("Invalid configuration: {:?}", err)).await?;
@@ -90,15 +137,51 @@ async fn main() -> Result<()> {
 
     let (identity, ca_cert) = tls::init(&directory)?;
 
+    let metrics_port: i64 = plugin.option(&OPTION_GRPC_METRICS_PORT).unwrap();
+    let metrics_port = u16::try_from(metrics_port).context("'grpc-metrics-port' out of range")?;
+    let metrics_addr: SocketAddr = ([127, 0, 0, 1], metrics_port).into();
+    let metrics = Metrics::new().context("creating metrics registry")?;
+
+    let high_water_pct: i64 = plugin.option(&OPTION_GRPC_NOTIFY_HIGH_WATER_MARK).unwrap();
+    let high_water_pct = match u64::try_from(high_water_pct) {
+        Ok(pct) if pct <= 100 => pct,
+        _ => {
+            plugin
+                .disable("'grpc-notify-high-water-mark' should be a percentage between 0 and 100")
+                .await?;
+            return Ok(());
+        }
+    };
+    let events_high_water_mark = (buffer_size as u64 * high_water_pct / 100) as usize;
+
     let state = PluginState {
         rpc_path: PathBuf::from(plugin.configuration().rpc_file.as_str()),
         identity,
         ca_cert,
         events: sender,
+        metrics: metrics.clone(),
+        // `broadcast::channel` rounds its capacity up to a power of two.
+        events_buffer_size: buffer_size.next_power_of_two(),
+        events_high_water_mark,
+        last_high_water_warn: Arc::new(Mutex::new(None)),
     };
 
     let plugin = plugin.start(state.clone()).await?;
 
+    let (shutdown_tx, _) = broadcast::channel(1);
+
+    let grpc_task = tokio::spawn(run_interface(
+        router_config,
+        state,
+        shutdown_tx.subscribe(),
+        shutdown_tx.subscribe(),
+    ));
+    let metrics_task = tokio::spawn(metrics::serve(
+        metrics_addr,
+        metrics,
+        shutdown_tx.subscribe(),
+    ));
+
     tokio::select! {
         _ = plugin.join() => {
         // This will likely never be shown, if we got here our
@@ -106,53 +189,231 @@ async fn main() -> Result<()> {
         // messages anymore.
             debug!("Plugin loop terminated")
         }
-        e = run_interface(router_config, state) => {
-            warn!("Error running grpc interface: {:?}", e)
+        _ = wait_for_termination() => {
+            debug!("Received termination signal")
         }
     }
+
+    // Tell both servers to stop accepting new work, then give in-flight
+    // requests (in particular streaming notification subscribers) a
+    // bounded grace period to drain before we drop their I/O outright.
+    let _ = shutdown_tx.send(());
+
+    match tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, grpc_task).await {
+        Ok(Ok(Err(err))) => warn!("Error running grpc interface: {:?}", err),
+        Ok(Err(err)) => warn!("grpc interface task panicked: {:?}", err),
+        Err(_) => warn!("grpc interface did not shut down within the grace period"),
+        Ok(Ok(Ok(()))) => {}
+    }
+    match tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, metrics_task).await {
+        Ok(Ok(Err(err))) => warn!("Error serving metrics: {:?}", err),
+        Ok(Err(err)) => warn!("metrics server task panicked: {:?}", err),
+        Err(_) => warn!("metrics server did not shut down within the grace period"),
+        Ok(Ok(Ok(()))) => {}
+    }
+
     Ok(())
 }
 
-async fn run_interface(router_config: GrpcRouterConfig, state: PluginState) -> Result<()> {
-    let bind_addr = router_config.socket_addr();
-    let identity = state.identity.to_tonic_identity();
-    let ca_cert = tonic::transport::Certificate::from_pem(state.ca_cert);
+/// Resolves once either SIGTERM or SIGINT is received, whichever comes
+/// first. `lightningd` sends SIGTERM when stopping or restarting a
+/// plugin, so this is what lets us drain connections instead of having
+/// them reset out from under clients.
+async fn wait_for_termination() {
+    let ctrl_c = tokio::signal::ctrl_c();
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            tokio::select! {
+                _ = sigterm.recv() => {}
+                _ = ctrl_c => {}
+            }
+        }
+        Err(err) => {
+            warn!("Failed to install SIGTERM handler: {:?}", err);
+            let _ = ctrl_c.await;
+        }
+    }
+}
+
+async fn run_interface(
+    router_config: GrpcRouterConfig,
+    state: PluginState,
+    mut shutdown_rx: broadcast::Receiver<()>,
+    health_shutdown_rx: broadcast::Receiver<()>,
+) -> Result<()> {
+    let node_service = NodeServer::new(
+        cln_grpc::Server::new(&state.rpc_path, state.events.clone())
+            .await
+            .context("creating NodeServer instance")?,
+    );
+    let shutdown = async move {
+        let _ = shutdown_rx.recv().await;
+    };
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(health::run(
+        state.rpc_path.clone(),
+        health_reporter,
+        health_shutdown_rx,
+    ));
+
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(cln_grpc::pb::FILE_DESCRIPTOR_SET)
+        .build()
+        .context("building grpc reflection service")?;
+
+    match router_config.transport() {
+        Transport::Tcp(bind_addr) => {
+            let identity = state.identity.to_tonic_identity();
+            let ca_cert = tonic::transport::Certificate::from_pem(state.ca_cert);
 
-    let tls = tonic::transport::ServerTlsConfig::new()
-        .identity(identity)
-        .client_ca_root(ca_cert);
+            let tls = tonic::transport::ServerTlsConfig::new()
+                .identity(identity)
+                .client_ca_root(ca_cert);
 
-    let server = tonic::transport::Server::builder()
-        .tls_config(tls)
-        .context("configuring tls")?
-        .add_service(NodeServer::new(
-            cln_grpc::Server::new(&state.rpc_path, state.events.clone())
+            let listener = tokio::net::TcpListener::bind(bind_addr)
                 .await
-                .context("creating NodeServer instance")?,
-        ))
-        .serve(bind_addr);
+                .with_context(|| format!("binding tcp socket {:?}", bind_addr))?;
+            let incoming = metrics::count_connections(
+                tokio_stream::wrappers::TcpListenerStream::new(listener),
+                state.metrics.grpc_connections_active.clone(),
+            );
 
-    debug!(
-        "Connecting to {:?} and serving grpc on {:?}",
-        &state.rpc_path, &bind_addr
-    );
+            debug!(
+                "Connecting to {:?} and serving grpc on {:?}",
+                &state.rpc_path, &bind_addr
+            );
 
-    server.await.context("serving requests")?;
+            tonic::transport::Server::builder()
+                .layer(panic_guard::PanicGuardLayer)
+                .layer(metrics::MetricsLayer::new(state.metrics.clone()))
+                .tls_config(tls)
+                .context("configuring tls")?
+                .add_service(node_service)
+                .add_service(health_service)
+                .add_service(reflection_service)
+                .serve_with_incoming_shutdown(incoming, shutdown)
+                .await
+                .context("serving requests")?;
+        }
+        Transport::Unix(socket_path) => {
+            // Filesystem permissions on the socket already gate access,
+            // so there is no need for mutual TLS on this transport.
+            if socket_path.exists() {
+                std::fs::remove_file(&socket_path)
+                    .with_context(|| format!("removing stale socket {:?}", socket_path))?;
+            }
+            let listener = UnixListener::bind(&socket_path)
+                .with_context(|| format!("binding unix socket {:?}", socket_path))?;
+            let incoming = metrics::count_connections(
+                UnixListenerStream::new(listener),
+                state.metrics.grpc_connections_active.clone(),
+            );
+
+            debug!(
+                "Connecting to {:?} and serving grpc on unix socket {:?}",
+                &state.rpc_path, &socket_path
+            );
+
+            tonic::transport::Server::builder()
+                .layer(panic_guard::PanicGuardLayer)
+                .layer(metrics::MetricsLayer::new(state.metrics.clone()))
+                .add_service(node_service)
+                .add_service(health_service)
+                .add_service(reflection_service)
+                .serve_with_incoming_shutdown(incoming, shutdown)
+                .await
+                .context("serving requests")?;
+        }
+    }
 
     Ok(())
 }
 
+/// Thin wrapper that isolates [`handle_notification_inner`] from panics:
+/// a malformed notification that triggers a panic deep in deserialization
+/// shouldn't be able to tear down the plugin's tokio runtime.
 async fn handle_notification(plugin: Plugin<PluginState>, value: serde_json::Value) -> Result<()> {
+    let state = plugin.state().clone();
+    match AssertUnwindSafe(handle_notification_inner(state, value))
+        .catch_unwind()
+        .await
+    {
+        Ok(result) => result,
+        Err(panic) => {
+            log::warn!(
+                "Recovered from a panic while handling a notification: {}",
+                panic_guard::panic_message(&*panic)
+            );
+            Ok(())
+        }
+    }
+}
+
+async fn handle_notification_inner(state: PluginState, value: serde_json::Value) -> Result<()> {
+    let state = &state;
+    state.metrics.notifications_received_total.inc();
+
     let notification: Result<Notification, _> = serde_json::from_value(value);
     match notification {
         Err(err) => {
             log::debug!("Failed to parse notification from lightningd {:?}", err);
         }
         Ok(notification) => {
-            if let Err(err) = plugin.state().events.send(notification) {
+            state
+                .metrics
+                .events_subscribers
+                .set(state.events.receiver_count() as i64);
+
+            // `len()` is the number of messages the slowest receiver
+            // hasn't caught up on yet. Once it reaches capacity, this
+            // send overwrites that receiver's oldest unread entry.
+            //
+            // This is the only per-subscriber lag signal available from
+            // the sender side: the exact number of notifications a given
+            // subscriber missed is only known to that subscriber, via the
+            // `RecvError::Lagged(n)` its own `recv()` call returns, and
+            // that loop lives inside `cln_grpc::Server` (an external
+            // crate, not present in this tree). Publishing occupancy here
+            // is the closest approximation obtainable from this crate: a
+            // reconnecting subscriber can be told it missed *up to*
+            // roughly this many notifications.
+            let occupancy = state.events.len();
+            state.metrics.notifications_buffer_occupancy.set(occupancy as i64);
+            if occupancy >= state.events_buffer_size {
+                state.metrics.notifications_lagged_total.inc();
+            }
+            warn_on_high_water_mark(state, occupancy);
+
+            if let Err(err) = state.events.send(notification) {
+                state.metrics.notifications_dropped_total.inc();
                 log::warn!("Failed to broadcast notification {:?}", err)
             }
         }
     };
     Ok(())
 }
+
+/// Emits a rate-limited warning once the notification broadcast buffer's
+/// occupancy crosses `events_high_water_mark`, so operators learn about
+/// a subscriber falling behind before it starts missing notifications
+/// outright (see `notifications_lagged_total`).
+fn warn_on_high_water_mark(state: &PluginState, occupancy: usize) {
+    if occupancy < state.events_high_water_mark {
+        return;
+    }
+
+    let mut last_warn = state.last_high_water_warn.lock().unwrap();
+    let now = Instant::now();
+    if last_warn.is_some_and(|t| now.duration_since(t) < HIGH_WATER_MARK_WARN_INTERVAL) {
+        return;
+    }
+    *last_warn = Some(now);
+    drop(last_warn);
+
+    log::warn!(
+        "Notification broadcast buffer occupancy is {}/{}, at or above the high-water mark; slow subscribers risk missing notifications",
+        occupancy,
+        state.events_buffer_size
+    );
+}
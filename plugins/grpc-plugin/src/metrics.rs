@@ -0,0 +1,284 @@
+use anyhow::{Context, Result};
+use futures::{Stream, StreamExt};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tonic::body::BoxBody;
+use tonic::transport::server::Connected;
+use tower::{Layer, Service};
+
+/// Collects the counters/gauges the plugin exposes on `/metrics`.
+///
+/// This is cheap to clone: every metric is itself an `Arc`-backed handle
+/// into the shared [`Registry`], so handing a copy to the tonic
+/// interceptor layer and to `handle_notification` doesn't duplicate any
+/// state.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub grpc_requests_total: IntCounterVec,
+    pub grpc_connections_active: IntGauge,
+    pub notifications_received_total: IntCounter,
+    pub notifications_dropped_total: IntCounter,
+    pub notifications_lagged_total: IntCounter,
+    pub notifications_buffer_occupancy: IntGauge,
+    pub events_subscribers: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+
+        let grpc_requests_total = IntCounterVec::new(
+            Opts::new(
+                "grpc_requests_total",
+                "Number of gRPC requests received, labelled by method",
+            ),
+            &["method"],
+        )
+        .context("creating grpc_requests_total metric")?;
+
+        let grpc_connections_active = IntGauge::new(
+            "grpc_connections_active",
+            "Number of gRPC (TCP or unix socket) connections currently open",
+        )
+        .context("creating grpc_connections_active metric")?;
+
+        let notifications_received_total = IntCounter::new(
+            "notifications_received_total",
+            "Total number of lightningd notifications received",
+        )
+        .context("creating notifications_received_total metric")?;
+
+        let notifications_dropped_total = IntCounter::new(
+            "notifications_dropped_total",
+            "Total number of notifications dropped because there were no active subscribers to receive them",
+        )
+        .context("creating notifications_dropped_total metric")?;
+
+        let notifications_lagged_total = IntCounter::new(
+            "notifications_lagged_total",
+            "Total number of notifications that overwrote an unread entry in the broadcast buffer, causing the slowest subscriber(s) to miss it",
+        )
+        .context("creating notifications_lagged_total metric")?;
+
+        let notifications_buffer_occupancy = IntGauge::new(
+            "notifications_buffer_occupancy",
+            "Number of unread notifications currently queued for the slowest subscriber; a reconnecting subscriber should expect to have missed roughly this many",
+        )
+        .context("creating notifications_buffer_occupancy metric")?;
+
+        let events_subscribers = IntGauge::new(
+            "events_subscribers",
+            "Current number of subscribers on the notification broadcast channel",
+        )
+        .context("creating events_subscribers metric")?;
+
+        registry.register(Box::new(grpc_requests_total.clone()))?;
+        registry.register(Box::new(grpc_connections_active.clone()))?;
+        registry.register(Box::new(notifications_received_total.clone()))?;
+        registry.register(Box::new(notifications_dropped_total.clone()))?;
+        registry.register(Box::new(notifications_lagged_total.clone()))?;
+        registry.register(Box::new(notifications_buffer_occupancy.clone()))?;
+        registry.register(Box::new(events_subscribers.clone()))?;
+
+        Ok(Self {
+            registry,
+            grpc_requests_total,
+            grpc_connections_active,
+            notifications_received_total,
+            notifications_dropped_total,
+            notifications_lagged_total,
+            notifications_buffer_occupancy,
+            events_subscribers,
+        })
+    }
+
+    fn gather(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .context("encoding metrics")?;
+        Ok(buffer)
+    }
+}
+
+/// Serves `GET /metrics` in Prometheus text format until `shutdown_rx`
+/// fires. Spawned as its own task in `main` so a scrape failure never
+/// takes the gRPC interface down with it.
+pub async fn serve(
+    bind_addr: SocketAddr,
+    metrics: Metrics,
+    mut shutdown_rx: tokio::sync::broadcast::Receiver<()>,
+) -> Result<()> {
+    let make_svc = make_service_fn(move |_conn| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                let metrics = metrics.clone();
+                async move {
+                    if req.uri().path() != "/metrics" {
+                        return Ok::<_, hyper::Error>(
+                            Response::builder()
+                                .status(404)
+                                .body(Body::from("not found"))
+                                .unwrap(),
+                        );
+                    }
+                    match metrics.gather() {
+                        Ok(buffer) => Ok(Response::new(Body::from(buffer))),
+                        Err(err) => Ok(Response::builder()
+                            .status(500)
+                            .body(Body::from(format!("{:?}", err)))
+                            .unwrap()),
+                    }
+                }
+            }))
+        }
+    });
+
+    Server::bind(&bind_addr)
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+            let _ = shutdown_rx.recv().await;
+        })
+        .await
+        .context("serving /metrics")
+}
+
+/// `tower::Layer` that counts gRPC requests per method. Connection-level
+/// metrics are tracked separately at accept time by [`count_connections`],
+/// since this layer wraps the per-request routing service rather than
+/// the transport.
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: Metrics,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Metrics,
+}
+
+impl<S> Service<Request<Body>> for MetricsService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = std::pin::Pin<
+        Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        self.metrics
+            .grpc_requests_total
+            .with_label_values(&[req.uri().path()])
+            .inc();
+
+        let mut inner = self.inner.clone();
+        Box::pin(async move { inner.call(req).await })
+    }
+}
+
+/// Wraps an accepted connection so that `gauge` is incremented for as
+/// long as the connection is open and decremented when it closes,
+/// however that happens (client disconnect, shutdown, or a panic
+/// unwinding through the connection task) — the decrement lives in
+/// `Drop`, not in a success path that a panic could skip.
+pub struct CountedIo<T> {
+    inner: T,
+    gauge: IntGauge,
+}
+
+impl<T> CountedIo<T> {
+    fn new(inner: T, gauge: IntGauge) -> Self {
+        gauge.inc();
+        Self { inner, gauge }
+    }
+}
+
+impl<T> Drop for CountedIo<T> {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountedIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountedIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: Connected> Connected for CountedIo<T> {
+    type ConnectInfo = T::ConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.inner.connect_info()
+    }
+}
+
+/// Wraps an incoming-connection stream (TCP or Unix) so `gauge` tracks
+/// the number of currently-open connections, counted at accept time
+/// rather than per request.
+pub fn count_connections<S, T, E>(
+    incoming: S,
+    gauge: IntGauge,
+) -> impl Stream<Item = Result<CountedIo<T>, E>>
+where
+    S: Stream<Item = Result<T, E>>,
+{
+    incoming.map(move |item| item.map(|io| CountedIo::new(io, gauge.clone())))
+}
@@ -0,0 +1,75 @@
+use futures::FutureExt;
+use hyper::{Body, Request, Response};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tower::{Layer, Service};
+
+/// Turns a caught panic's payload into a loggable string, mirroring what
+/// the default panic hook prints for `&str` and `String` payloads.
+pub(crate) fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+/// `tower::Layer` that catches a panic anywhere in the wrapped gRPC
+/// service and turns it into a `tonic::Status::internal` response
+/// instead of letting it unwind into the tokio runtime, so one
+/// malformed request can't take down the whole plugin. A panic raised
+/// while a response body is being streamed, after the service has
+/// returned its response, is not caught here.
+#[derive(Clone, Default)]
+pub struct PanicGuardLayer;
+
+impl<S> Layer<S> for PanicGuardLayer {
+    type Service = PanicGuardService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PanicGuardService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct PanicGuardService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for PanicGuardService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        Box::pin(async move {
+            match AssertUnwindSafe(async move { inner.call(req).await })
+                .catch_unwind()
+                .await
+            {
+                Ok(result) => result,
+                Err(panic) => {
+                    log::warn!(
+                        "Recovered from a panic while handling a grpc request: {}",
+                        panic_message(&*panic)
+                    );
+                    Ok(tonic::Status::internal("internal error").to_http())
+                }
+            }
+        })
+    }
+}
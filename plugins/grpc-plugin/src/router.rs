@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Context, Result};
+use cln_plugin::Plugin;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use crate::{PluginState, OPTION_GRPC_HOST, OPTION_GRPC_PORT};
+
+/// The concrete transport `run_interface` should bind to, as decided by
+/// [`GrpcRouterConfig::from_configured_plugin`].
+#[derive(Clone, Debug)]
+pub enum Transport {
+    /// Plain TCP, always served with mutual TLS.
+    Tcp(SocketAddr),
+    /// A Unix domain socket, served without TLS since filesystem
+    /// permissions already gate who can connect.
+    Unix(PathBuf),
+}
+
+/// Prefix used by `grpc-host` to select the Unix domain socket
+/// transport, mirroring the `grpc+unix://` scheme convention used
+/// elsewhere for local-only gRPC endpoints.
+const UNIX_SCHEME: &str = "unix://";
+
+#[derive(Clone, Debug)]
+pub struct GrpcRouterConfig {
+    transport: Transport,
+}
+
+impl GrpcRouterConfig {
+    pub fn transport(&self) -> Transport {
+        self.transport.clone()
+    }
+
+    /// Reads `grpc-host` (and, for the TCP case, `grpc-port`) from the
+    /// configured plugin and decides which transport to serve on.
+    ///
+    /// `grpc-host` may either be a bare IP address (`127.0.0.1`, `::1`, ...;
+    /// host names are not resolved),
+    /// in which case we bind a TCP socket on `grpc-port`, or a
+    /// `unix:///path/to/socket` URI, in which case we bind that path as
+    /// a Unix domain socket and ignore `grpc-port` entirely.
+    ///
+    /// Both options carry defaults, so this always resolves to a
+    /// transport; it only fails if `grpc-host`/`grpc-port` are set to
+    /// something unparseable or `grpc-port` is out of range.
+    pub fn from_configured_plugin(plugin: &Plugin<PluginState>) -> Result<Self> {
+        let host: String = plugin
+            .option(&OPTION_GRPC_HOST)
+            .ok_or_else(|| anyhow!("missing 'grpc-host' option"))?;
+
+        if let Some(path) = host.strip_prefix(UNIX_SCHEME) {
+            return Ok(Self {
+                transport: Transport::Unix(PathBuf::from(path)),
+            });
+        }
+
+        let port: i64 = plugin
+            .option(&OPTION_GRPC_PORT)
+            .ok_or_else(|| anyhow!("missing 'grpc-port' option"))?;
+        let port = u16::try_from(port).context("'grpc-port' out of range")?;
+
+        let ip = host
+            .parse::<IpAddr>()
+            .with_context(|| format!("parsing 'grpc-host' {:?} as an IP address", host))?;
+        let addr = SocketAddr::new(ip, port);
+
+        Ok(Self {
+            transport: Transport::Tcp(addr),
+        })
+    }
+}
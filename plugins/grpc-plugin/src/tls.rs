@@ -0,0 +1,39 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// A TLS identity (certificate + private key pair) loaded from the
+/// plugin's `certs` directory, used to authenticate the server to its
+/// clients. Clients are verified against the CA certificate that
+/// [`init`] returns alongside it.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl Identity {
+    pub fn to_tonic_identity(&self) -> tonic::transport::Identity {
+        tonic::transport::Identity::from_pem(self.cert.clone(), self.key.clone())
+    }
+}
+
+fn certs_dir(directory: &Path) -> PathBuf {
+    directory.join("certs")
+}
+
+/// Loads the server identity and CA certificate from `<directory>/certs`.
+///
+/// The certificate hierarchy is expected to have been provisioned ahead
+/// of time (e.g. by `lightningd`'s `makeCert.py` or an operator-supplied
+/// CA); this function only reads the files that are already on disk.
+pub fn init(directory: &Path) -> Result<(Identity, Vec<u8>)> {
+    let certs_dir = certs_dir(directory);
+
+    let cert = std::fs::read(certs_dir.join("server-chain.pem"))
+        .context("reading server certificate chain")?;
+    let key =
+        std::fs::read(certs_dir.join("server-key.pem")).context("reading server private key")?;
+    let ca_cert = std::fs::read(certs_dir.join("ca.pem")).context("reading CA certificate")?;
+
+    Ok((Identity { cert, key }, ca_cert))
+}